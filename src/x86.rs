@@ -1,9 +1,10 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Name(pub String);
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Label(pub String);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -60,6 +61,18 @@ impl Register {
         Register::R8,
         Register::R9,
     ];
+
+    /// The registers the allocator is free to hand out, in preference order:
+    /// every caller-saved register followed by the callee-saved ones except
+    /// `RSP`/`RBP`, which are reserved for the stack frame.
+    pub fn allocatable() -> Vec<Register> {
+        Register::CALLER_SAVED
+            .iter()
+            .chain(Register::CALLEE_SAVED.iter())
+            .filter(|reg| !matches!(reg, Register::RSP | Register::RBP))
+            .cloned()
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -140,6 +153,25 @@ impl Instr {
         set
     }
 
+    /// Rewrite every operand of the instruction through `f`, leaving labels
+    /// and arities untouched. Used by the spill pass to substitute registers
+    /// and stack slots for variables.
+    pub fn map_operands(&self, mut f: impl FnMut(&Operand) -> Operand) -> Instr {
+        use InstrF::*;
+        match self {
+            AddQ(src, dst) => AddQ(f(src), f(dst)),
+            SubQ(src, dst) => SubQ(f(src), f(dst)),
+            NegQ(dst) => NegQ(f(dst)),
+            MovQ(src, dst) => MovQ(f(src), f(dst)),
+            PushQ(op) => PushQ(f(op)),
+            PopQ(op) => PopQ(f(op)),
+            CallQ(label, arity) => CallQ(label.clone(), arity.clone()),
+            Jmp(label) => Jmp(label.clone()),
+            Syscall => Syscall,
+            RetQ => RetQ,
+        }
+    }
+
     pub fn defs(&self) -> HashSet<Operand> {
         use InstrF::*;
         let mut set = HashSet::new();
@@ -170,7 +202,15 @@ pub struct Block(pub Vec<Instr>);
 
 impl Block {
     pub fn liveness(&self) -> Vec<Liveness> {
-        let mut after = HashSet::new();
+        self.liveness_from(HashSet::new())
+    }
+
+    /// The backward sweep of [`Block::liveness`], but seeded with the set of
+    /// operands live on exit from the block. Straight-line blocks start from an
+    /// empty set; blocks inside a [`Program`] are seeded with their computed
+    /// `live_out`.
+    pub fn liveness_from(&self, live_out: HashSet<Operand>) -> Vec<Liveness> {
+        let mut after = live_out;
         let mut liveness = Vec::with_capacity(self.0.len());
 
         for instr in self.0.iter().rev() {
@@ -192,6 +232,813 @@ impl Block {
         liveness.reverse();
         liveness
     }
+
+    /// Drop every `MovQ(Var src, Var dst)` whose operands were assigned the
+    /// same register — after coalescing these copies are no-ops. `coloring`
+    /// must already resolve coalesced variables to their shared register.
+    pub fn remove_redundant_moves(&self, coloring: &HashMap<Name, Register>) -> Block {
+        let instrs = self
+            .0
+            .iter()
+            .filter(|instr| {
+                if let InstrF::MovQ(Operand::Var(src), Operand::Var(dst)) = instr {
+                    if let (Some(rs), Some(rd)) = (coloring.get(src), coloring.get(dst)) {
+                        return rs != rd;
+                    }
+                }
+                true
+            })
+            .cloned()
+            .collect();
+        Block(instrs)
+    }
+}
+
+/// A function as a control-flow graph: an ordered list of labelled blocks.
+///
+/// Successor edges are derived from each block's terminator — a `Jmp(Label)`
+/// jumps to its target, a `RetQ` has no successors, and any other ending falls
+/// through to the block that follows it in program order.
+pub struct Program(pub Vec<(Label, Block)>);
+
+impl Program {
+    /// Labels of the blocks control may reach directly from `label`.
+    pub fn successors(&self, label: &Label) -> Vec<Label> {
+        let index = match self.0.iter().position(|(l, _)| l == label) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        let block = &self.0[index].1;
+
+        match block.0.last() {
+            Some(InstrF::Jmp(target)) => vec![target.clone()],
+            Some(InstrF::RetQ) => Vec::new(),
+            _ => self.0.get(index + 1).map(|(l, _)| l.clone()).into_iter().collect(),
+        }
+    }
+
+    /// Upward-exposed uses and all defs of a single block: `uses` are the
+    /// operands read before any local def shadows them, `defs` is every operand
+    /// the block writes.
+    fn summary(block: &Block) -> (HashSet<Operand>, HashSet<Operand>) {
+        let mut uses = HashSet::new();
+        let mut defs = HashSet::new();
+        for instr in &block.0 {
+            for op in instr.uses() {
+                if !defs.contains(&op) {
+                    uses.insert(op);
+                }
+            }
+            defs.extend(instr.defs());
+        }
+        (uses, defs)
+    }
+
+    /// Reverse-postorder of the blocks reachable from the entry (the first
+    /// block), which lets the backward liveness iteration converge quickly.
+    fn reverse_postorder(&self) -> Vec<Label> {
+        let mut postorder = Vec::new();
+        let mut visited = HashSet::new();
+        if let Some((entry, _)) = self.0.first() {
+            self.postorder(entry, &mut visited, &mut postorder);
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    fn postorder(&self, label: &Label, visited: &mut HashSet<Label>, out: &mut Vec<Label>) {
+        if !visited.insert(label.clone()) {
+            return;
+        }
+        for succ in self.successors(label) {
+            self.postorder(&succ, visited, out);
+        }
+        out.push(label.clone());
+    }
+
+    /// Iterate the dataflow equations to a fixed point, returning the
+    /// `live_in` and `live_out` set of every block. All live-in sets start
+    /// empty and are refined until no set changes.
+    pub fn live_sets(&self) -> (HashMap<Label, HashSet<Operand>>, HashMap<Label, HashSet<Operand>>) {
+        let summaries: HashMap<Label, (HashSet<Operand>, HashSet<Operand>)> = self
+            .0
+            .iter()
+            .map(|(l, b)| (l.clone(), Self::summary(b)))
+            .collect();
+
+        let mut live_in: HashMap<Label, HashSet<Operand>> =
+            self.0.iter().map(|(l, _)| (l.clone(), HashSet::new())).collect();
+        let mut live_out: HashMap<Label, HashSet<Operand>> = live_in.clone();
+
+        let order = self.reverse_postorder();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for label in &order {
+                let out: HashSet<Operand> = self
+                    .successors(label)
+                    .iter()
+                    .flat_map(|s| live_in[s].iter().cloned())
+                    .collect();
+
+                let (uses, defs) = &summaries[label];
+                let new_in: HashSet<Operand> = uses
+                    .union(&out.difference(defs).cloned().collect())
+                    .cloned()
+                    .collect();
+
+                live_out.insert(label.clone(), out);
+                if new_in != live_in[label] {
+                    live_in.insert(label.clone(), new_in);
+                    changed = true;
+                }
+            }
+        }
+
+        (live_in, live_out)
+    }
+
+    /// Per-instruction liveness for every block, each block's backward sweep
+    /// seeded with its `live_out` from the fixed-point analysis.
+    pub fn liveness(&self) -> HashMap<Label, Vec<Liveness>> {
+        let (_, live_out) = self.live_sets();
+        self.0
+            .iter()
+            .map(|(label, block)| {
+                let seed = live_out.get(label).cloned().unwrap_or_default();
+                (label.clone(), block.liveness_from(seed))
+            })
+            .collect()
+    }
+
+    /// Color the whole function with `k` registers, building one interference
+    /// graph across every block (see [`InterferenceGraph::for_program`])
+    /// instead of allocating each block in isolation.
+    pub fn color(&self, k: usize) -> Result<HashMap<Name, Register>, Vec<Name>> {
+        InterferenceGraph::for_program(self).color(k)
+    }
+}
+
+/// The live range of a single variable over a flattened instruction stream,
+/// from the index of its first definition to that of its last use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveInterval {
+    pub name: Name,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Poletto & Sarkar's linear-scan allocator: a fast, linear-time alternative
+/// to graph coloring that assigns registers by sweeping live intervals in a
+/// single pass. It trades allocation quality for speed, which makes it a handy
+/// point of comparison against [`InterferenceGraph::color`].
+pub struct LinearScan {
+    intervals: Vec<LiveInterval>,
+}
+
+impl LinearScan {
+    /// Derive one [`LiveInterval`] per variable from a block's liveness,
+    /// sorted by increasing start point ready for the scan.
+    pub fn new(liveness: &[Liveness]) -> Self {
+        let mut start: HashMap<Name, usize> = HashMap::new();
+        let mut end: HashMap<Name, usize> = HashMap::new();
+
+        for (i, live) in liveness.iter().enumerate() {
+            for def in live.instr.defs() {
+                if let Operand::Var(name) = def {
+                    start.entry(name.clone()).or_insert(i);
+                    end.entry(name).or_insert(i);
+                }
+            }
+            for used in live.instr.uses() {
+                if let Operand::Var(name) = used {
+                    start.entry(name.clone()).or_insert(i);
+                    end.insert(name, i);
+                }
+            }
+        }
+
+        let mut intervals: Vec<LiveInterval> = start
+            .into_iter()
+            .map(|(name, start)| {
+                let end = end[&name];
+                LiveInterval { name, start, end }
+            })
+            .collect();
+        intervals.sort_by_key(|iv| iv.start);
+
+        LinearScan { intervals }
+    }
+
+    pub fn intervals(&self) -> &[LiveInterval] {
+        &self.intervals
+    }
+
+    /// Scan the intervals with `k` available registers, returning a map from
+    /// each variable to the [`Operand`] holding it — a [`Operand::Reg`] when a
+    /// register was free, otherwise a spill slot `Mem(RBP, -8*n)`.
+    pub fn allocate(&self, k: usize) -> HashMap<Name, Operand> {
+        let palette: Vec<Register> = Register::allocatable().into_iter().take(k).collect();
+        let mut assignment: HashMap<Name, Operand> = HashMap::new();
+        // Currently-live intervals holding a register, kept sorted by end so
+        // the farthest-ending one (the cheapest to spill) sits at the back.
+        let mut active: Vec<(LiveInterval, Register)> = Vec::new();
+        let mut next_slot: i64 = 1;
+
+        for interval in &self.intervals {
+            active.retain(|(iv, _)| iv.end >= interval.start);
+
+            let used: HashSet<&Register> = active.iter().map(|(_, r)| r).collect();
+            match palette.iter().find(|r| !used.contains(r)) {
+                Some(reg) => {
+                    assignment.insert(interval.name.clone(), Operand::Reg(reg.clone()));
+                    active.push((interval.clone(), reg.clone()));
+                }
+                None => {
+                    // No free register: spill whichever of this interval and
+                    // the farthest-ending active interval lives longer.
+                    let spill = active.last().cloned();
+                    match spill {
+                        Some((victim, reg)) if victim.end > interval.end => {
+                            assignment.insert(interval.name.clone(), Operand::Reg(reg.clone()));
+                            assignment
+                                .insert(victim.name.clone(), Operand::Mem(Register::RBP, -8 * next_slot));
+                            next_slot += 1;
+                            active.pop();
+                            active.push((interval.clone(), reg));
+                        }
+                        _ => {
+                            assignment.insert(
+                                interval.name.clone(),
+                                Operand::Mem(Register::RBP, -8 * next_slot),
+                            );
+                            next_slot += 1;
+                        }
+                    }
+                }
+            }
+
+            active.sort_by_key(|(iv, _)| iv.end);
+        }
+
+        assignment
+    }
+}
+
+/// Undirected interference graph over the program's variables.
+///
+/// Two variables interfere when one is defined at a point where the other is
+/// live, which forbids them from sharing a register. Physical registers that a
+/// variable is live across (for instance the caller-saved registers clobbered
+/// by a `CallQ`) are recorded per node as forbidden colors rather than as
+/// graph nodes of their own.
+pub struct InterferenceGraph {
+    adj: HashMap<Name, HashSet<Name>>,
+    forbidden: HashMap<Name, HashSet<Register>>,
+    ranges: HashMap<Name, usize>,
+    /// `MovQ(Var, Var)` pairs eligible for coalescing.
+    moves: Vec<(Name, Name)>,
+    /// Union-find aliases mapping each coalesced variable to its representative.
+    alias: HashMap<Name, Name>,
+}
+
+impl InterferenceGraph {
+    /// Build the graph from a block's liveness analysis. For every instruction
+    /// an edge is drawn between each def and every operand still live in its
+    /// `after` set, except for a `MovQ src, dst` whose source and destination
+    /// need not interfere — leaving them free to be coalesced later.
+    pub fn new(liveness: &[Liveness]) -> Self {
+        let mut adj: HashMap<Name, HashSet<Name>> = HashMap::new();
+        let mut forbidden: HashMap<Name, HashSet<Register>> = HashMap::new();
+        let mut moves: Vec<(Name, Name)> = Vec::new();
+
+        let ensure = |adj: &mut HashMap<Name, HashSet<Name>>, op: &Operand| {
+            if let Operand::Var(name) = op {
+                adj.entry(name.clone()).or_default();
+            }
+        };
+
+        for live in liveness {
+            let moved = match &live.instr {
+                InstrF::MovQ(src, dst) => Some((src.clone(), dst.clone())),
+                _ => None,
+            };
+
+            if let Some((Operand::Var(src), Operand::Var(dst))) = &moved {
+                moves.push((src.clone(), dst.clone()));
+            }
+
+            for def in live.instr.defs() {
+                ensure(&mut adj, &def);
+                for other in &live.after {
+                    if *other == def {
+                        continue;
+                    }
+                    if moved
+                        .as_ref()
+                        .is_some_and(|(src, dst)| def == *dst && *other == *src)
+                    {
+                        continue;
+                    }
+                    match (&def, other) {
+                        (Operand::Var(a), Operand::Var(b)) => {
+                            adj.entry(a.clone()).or_default().insert(b.clone());
+                            adj.entry(b.clone()).or_default().insert(a.clone());
+                        }
+                        (Operand::Var(a), Operand::Reg(r)) => {
+                            forbidden.entry(a.clone()).or_default().insert(r.clone());
+                        }
+                        (Operand::Reg(r), Operand::Var(b)) => {
+                            ensure(&mut adj, other);
+                            forbidden.entry(b.clone()).or_default().insert(r.clone());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let ranges = Self::live_ranges(liveness);
+        InterferenceGraph {
+            adj,
+            forbidden,
+            ranges,
+            moves,
+            alias: HashMap::new(),
+        }
+    }
+
+    /// Build one interference graph for an entire [`Program`] by constructing
+    /// each block's graph against its own seeded liveness (see
+    /// [`Program::liveness`]) and unioning the results. Cross-block
+    /// interference isn't lost by treating blocks separately here: a block's
+    /// liveness is already seeded from its `live_out` over the whole CFG, so
+    /// a variable live across a branch still shows up in the `after` set of
+    /// the instruction that needs it to interfere against.
+    pub fn for_program(program: &Program) -> Self {
+        let liveness = program.liveness();
+        let mut merged = InterferenceGraph {
+            adj: HashMap::new(),
+            forbidden: HashMap::new(),
+            ranges: HashMap::new(),
+            moves: Vec::new(),
+            alias: HashMap::new(),
+        };
+
+        for block_liveness in liveness.values() {
+            let graph = InterferenceGraph::new(block_liveness);
+            for (name, neighbors) in graph.adj {
+                merged.adj.entry(name).or_default().extend(neighbors);
+            }
+            for (name, regs) in graph.forbidden {
+                merged.forbidden.entry(name).or_default().extend(regs);
+            }
+            for (name, range) in graph.ranges {
+                merged.ranges.entry(name).and_modify(|r| *r += range).or_insert(range);
+            }
+            merged.moves.extend(graph.moves);
+        }
+
+        merged
+    }
+
+    /// Length of each variable's live range, measured as the span of
+    /// instruction indices over which it appears live. Used to rank spill
+    /// candidates during coloring.
+    fn live_ranges(liveness: &[Liveness]) -> HashMap<Name, usize> {
+        let mut first: HashMap<Name, usize> = HashMap::new();
+        let mut last: HashMap<Name, usize> = HashMap::new();
+
+        for (i, live) in liveness.iter().enumerate() {
+            for op in live.before.iter().chain(live.after.iter()) {
+                if let Operand::Var(name) = op {
+                    first.entry(name.clone()).or_insert(i);
+                    last.insert(name.clone(), i);
+                }
+            }
+        }
+
+        first
+            .into_iter()
+            .map(|(name, start)| {
+                let end = last[&name];
+                (name, end - start + 1)
+            })
+            .collect()
+    }
+
+    /// Run the classic simplify/select graph-coloring allocator with `k`
+    /// registers. Low-degree nodes are pushed onto a stack and removed until
+    /// only high-degree nodes remain, at which point the lowest-priority node
+    /// (`live-range length / degree`) is pushed as an optimistic spill
+    /// candidate. On the way back up each node is given the lowest-indexed
+    /// register not claimed by a colored neighbor. Any node that finds no free
+    /// register is an actual spill; if there are spills they are returned as
+    /// the `Err` variant.
+    pub fn color(&self, k: usize) -> Result<HashMap<Name, Register>, Vec<Name>> {
+        let mut degree: HashMap<Name, usize> =
+            self.adj.iter().map(|(n, e)| (n.clone(), e.len())).collect();
+        let mut removed: HashSet<Name> = HashSet::new();
+        let mut stack: Vec<Name> = Vec::with_capacity(self.adj.len());
+
+        while removed.len() < self.adj.len() {
+            let simplifiable = self
+                .adj
+                .keys()
+                .find(|n| !removed.contains(*n) && degree[*n] < k)
+                .cloned();
+
+            let node = simplifiable.unwrap_or_else(|| {
+                // No trivially colorable node left: optimistically pick the
+                // cheapest spill candidate, i.e. the lowest spill priority.
+                self.adj
+                    .keys()
+                    .filter(|n| !removed.contains(*n))
+                    .min_by(|a, b| {
+                        let pa = self.spill_priority(a, degree[*a]);
+                        let pb = self.spill_priority(b, degree[*b]);
+                        pa.partial_cmp(&pb).unwrap_or(std::cmp::Ordering::Equal)
+                    })
+                    .cloned()
+                    .expect("graph is non-empty while nodes remain")
+            });
+
+            for neighbor in &self.adj[&node] {
+                if !removed.contains(neighbor) {
+                    *degree.get_mut(neighbor).unwrap() -= 1;
+                }
+            }
+            removed.insert(node.clone());
+            stack.push(node);
+        }
+
+        let palette: Vec<Register> = Register::allocatable().into_iter().take(k).collect();
+        let mut coloring: HashMap<Name, Register> = HashMap::new();
+        let mut spills: Vec<Name> = Vec::new();
+
+        while let Some(node) = stack.pop() {
+            let mut taken: HashSet<Register> =
+                self.forbidden.get(&node).cloned().unwrap_or_default();
+            for neighbor in &self.adj[&node] {
+                if let Some(reg) = coloring.get(neighbor) {
+                    taken.insert(reg.clone());
+                }
+            }
+
+            match palette.iter().find(|reg| !taken.contains(reg)) {
+                Some(reg) => {
+                    coloring.insert(node, reg.clone());
+                }
+                None => spills.push(node),
+            }
+        }
+
+        if spills.is_empty() {
+            Ok(coloring)
+        } else {
+            Err(spills)
+        }
+    }
+
+    /// Spill priority of a node: shorter live ranges and higher degree make a
+    /// node a cheaper spill. A degree of zero can never be a spill candidate,
+    /// so we guard against division by zero.
+    fn spill_priority(&self, name: &Name, degree: usize) -> f64 {
+        let range = *self.ranges.get(name).unwrap_or(&1) as f64;
+        range / degree.max(1) as f64
+    }
+
+    /// Representative of `name` under the current coalescing aliases.
+    pub fn representative(&self, name: &Name) -> Name {
+        let mut current = name.clone();
+        while let Some(parent) = self.alias.get(&current) {
+            current = parent.clone();
+        }
+        current
+    }
+
+    /// Conservatively coalesce move-related variables using Briggs' test: two
+    /// non-interfering nodes `a` and `b` are merged only when their combined
+    /// node would have fewer than `k` neighbors of significant degree
+    /// (degree `>= k`), which guarantees the merge can never turn a colorable
+    /// graph uncolorable. Interference edges are rewritten onto the surviving
+    /// representative, and the alias map (consulted via
+    /// [`InterferenceGraph::representative`]) records which variables were
+    /// merged.
+    pub fn coalesce(&mut self, k: usize) {
+        loop {
+            let mut merged = false;
+            for (a, b) in self.moves.clone() {
+                let ra = self.representative(&a);
+                let rb = self.representative(&b);
+                if ra == rb || self.adj[&ra].contains(&rb) {
+                    continue;
+                }
+
+                let mut combined: HashSet<Name> =
+                    self.adj[&ra].union(&self.adj[&rb]).cloned().collect();
+                combined.remove(&ra);
+                combined.remove(&rb);
+                let significant = combined.iter().filter(|n| self.adj[*n].len() >= k).count();
+
+                if significant < k {
+                    self.merge(&ra, &rb);
+                    merged = true;
+                }
+            }
+            if !merged {
+                break;
+            }
+        }
+    }
+
+    /// Merge `victim` into `keep`, moving all of the victim's interference
+    /// edges, forbidden colors, and live range onto the representative.
+    fn merge(&mut self, keep: &Name, victim: &Name) {
+        let neighbors = self.adj.remove(victim).unwrap_or_default();
+        for neighbor in &neighbors {
+            if let Some(edges) = self.adj.get_mut(neighbor) {
+                edges.remove(victim);
+                if neighbor != keep {
+                    edges.insert(keep.clone());
+                }
+            }
+        }
+
+        let keep_edges = self.adj.entry(keep.clone()).or_default();
+        for neighbor in neighbors {
+            if neighbor != *keep {
+                keep_edges.insert(neighbor);
+            }
+        }
+
+        if let Some(regs) = self.forbidden.remove(victim) {
+            self.forbidden.entry(keep.clone()).or_default().extend(regs);
+        }
+        let victim_range = self.ranges.remove(victim).unwrap_or(0);
+        *self.ranges.entry(keep.clone()).or_insert(0) += victim_range;
+
+        self.alias.insert(victim.clone(), keep.clone());
+    }
+}
+
+/// Realizes spill decisions in the instruction stream and lays out the stack
+/// frame, turning an allocated block into assemblable code.
+///
+/// Each spilled variable is given a unique slot `Mem(RBP, -8*n)`; instructions
+/// that touch a spilled operand gain a load into a scratch register before the
+/// op and a store back afterward. The block is finally wrapped in a
+/// prologue/epilogue that establishes the frame and saves any callee-saved
+/// registers the allocation uses.
+pub struct Spiller {
+    slots: HashMap<Name, i64>,
+    frame_size: i64,
+}
+
+impl Spiller {
+    /// Every instruction has at most two operands, so reloading the worst
+    /// case (both spilled) never needs more than two scratch registers.
+    pub const SCRATCH_REGISTERS: usize = 2;
+
+    /// Number of registers left for the allocator to hand out once
+    /// [`Self::SCRATCH_REGISTERS`] are reserved for spill reloads. Callers
+    /// that expect spills should run `color`/`allocate` with this many
+    /// registers rather than the full `Register::allocatable().len()`, so
+    /// the reserved scratch registers are never also handed to a variable.
+    pub fn usable_registers() -> usize {
+        Register::allocatable().len() - Self::SCRATCH_REGISTERS
+    }
+
+    /// Assign each spilled variable a stack slot and size the frame, rounding
+    /// the spill area up to the 16-byte stack alignment the ABI requires.
+    pub fn new(spilled: &HashSet<Name>) -> Self {
+        let mut names: Vec<Name> = spilled.iter().cloned().collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let slots: HashMap<Name, i64> = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name, -8 * (i as i64 + 1)))
+            .collect();
+
+        let bytes = 8 * slots.len() as i64;
+        let frame_size = (bytes + 15) & !15;
+
+        Spiller { slots, frame_size }
+    }
+
+    pub fn frame_size(&self) -> i64 {
+        self.frame_size
+    }
+
+    /// Rewrite `block` against `allocation` (the register chosen for each
+    /// non-spilled variable), inserting loads/stores for spilled operands and
+    /// wrapping the result in a prologue and epilogue.
+    pub fn assemble(&self, block: &Block, allocation: &HashMap<Name, Register>) -> Block {
+        let body = self.rewrite_spills(block, allocation);
+        self.frame(body, allocation)
+    }
+
+    /// Rewrite every block of a whole-function `program` against a single
+    /// shared stack frame: spilled operands are reloaded/stored block by
+    /// block exactly as in [`Self::assemble`], but the prologue is only
+    /// prepended to the entry block and the epilogue is inserted before
+    /// every `RetQ`, not just the one at the end of an isolated block — a
+    /// function can return from more than one block of its CFG.
+    pub fn assemble_program(&self, program: &Program, allocation: &HashMap<Name, Register>) -> Program {
+        let callee = self.callee_saved(allocation);
+        let entry = program.0.first().map(|(label, _)| label.clone());
+
+        let blocks = program
+            .0
+            .iter()
+            .map(|(label, block)| {
+                let mut body =
+                    self.with_epilogue_before_returns(self.rewrite_spills(block, allocation), &callee);
+                if entry.as_ref() == Some(label) {
+                    let mut prologue = self.prologue(&callee);
+                    prologue.append(&mut body);
+                    body = prologue;
+                }
+                (label.clone(), Block(body))
+            })
+            .collect();
+
+        Program(blocks)
+    }
+
+    /// Insert loads/stores around every spilled operand in `block`, without
+    /// any frame wrapping — the piece shared by [`Self::assemble`] and
+    /// [`Self::assemble_program`].
+    fn rewrite_spills(&self, block: &Block, allocation: &HashMap<Name, Register>) -> Vec<Instr> {
+        // Scratch registers for reloading spilled operands: the tail of the
+        // palette that `allocation` is expected to have left untouched (see
+        // `usable_registers`), not "whatever the allocation didn't use" —
+        // under register pressure that set can be empty even though the
+        // allocator correctly stayed within its budget.
+        let scratch: Vec<Register> = Register::allocatable()
+            .into_iter()
+            .skip(Self::usable_registers())
+            .collect();
+        debug_assert!(
+            allocation.values().all(|reg| !scratch.contains(reg)),
+            "allocation uses a reserved scratch register; color()/allocate() must be run with \
+             Spiller::usable_registers(), not the full Register::allocatable() palette"
+        );
+
+        let mut body: Vec<Instr> = Vec::new();
+        for instr in &block.0 {
+            let reads: Vec<Name> = instr
+                .uses()
+                .into_iter()
+                .filter_map(|op| self.spilled_name(&op))
+                .collect();
+            let writes: Vec<Name> = instr
+                .defs()
+                .into_iter()
+                .filter_map(|op| self.spilled_name(&op))
+                .collect();
+
+            // Map each spilled variable in this instruction to its own scratch.
+            let mut touched: Vec<Name> = reads.iter().chain(writes.iter()).cloned().collect();
+            touched.sort_by(|a, b| a.0.cmp(&b.0));
+            touched.dedup();
+            let scratch_of: HashMap<Name, Register> = touched
+                .iter()
+                .cloned()
+                .zip(scratch.iter().cloned())
+                .collect();
+
+            for name in &reads {
+                body.push(InstrF::MovQ(
+                    Operand::Mem(Register::RBP, self.slots[name]),
+                    Operand::Reg(scratch_of[name].clone()),
+                ));
+            }
+
+            body.push(instr.map_operands(|op| self.resolve(op, allocation, &scratch_of)));
+
+            for name in &writes {
+                body.push(InstrF::MovQ(
+                    Operand::Reg(scratch_of[name].clone()),
+                    Operand::Mem(Register::RBP, self.slots[name]),
+                ));
+            }
+        }
+
+        body
+    }
+
+    /// Name of a spilled variable operand, if any.
+    fn spilled_name(&self, op: &Operand) -> Option<Name> {
+        match op {
+            Operand::Var(name) if self.slots.contains_key(name) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    /// Resolve a variable to its register, stack slot, or scratch register;
+    /// non-variable operands pass through unchanged.
+    fn resolve(
+        &self,
+        op: &Operand,
+        allocation: &HashMap<Name, Register>,
+        scratch_of: &HashMap<Name, Register>,
+    ) -> Operand {
+        match op {
+            Operand::Var(name) => {
+                if let Some(reg) = scratch_of.get(name) {
+                    Operand::Reg(reg.clone())
+                } else if let Some(reg) = allocation.get(name) {
+                    Operand::Reg(reg.clone())
+                } else {
+                    op.clone()
+                }
+            }
+            _ => op.clone(),
+        }
+    }
+
+    /// Wrap `body` in the prologue/epilogue, saving and restoring the
+    /// callee-saved registers the allocation touches.
+    fn frame(&self, body: Vec<Instr>, allocation: &HashMap<Name, Register>) -> Block {
+        use InstrF::*;
+
+        let callee = self.callee_saved(allocation);
+        let mut out = self.prologue(&callee);
+
+        // Copy the body, turning its terminating `RetQ` into the epilogue.
+        let mut tail_ret = false;
+        for instr in body {
+            if matches!(instr, RetQ) {
+                tail_ret = true;
+            } else {
+                out.push(instr);
+            }
+        }
+
+        out.extend(self.epilogue(&callee));
+        if tail_ret {
+            out.push(RetQ);
+        }
+
+        Block(out)
+    }
+
+    /// Callee-saved registers the allocation actually touches, in the order
+    /// they're pushed by the prologue (and popped in reverse by the
+    /// epilogue).
+    fn callee_saved(&self, allocation: &HashMap<Name, Register>) -> Vec<Register> {
+        let used: HashSet<&Register> = allocation.values().collect();
+        Register::CALLEE_SAVED
+            .iter()
+            .filter(|r| !matches!(r, Register::RSP | Register::RBP) && used.contains(r))
+            .cloned()
+            .collect()
+    }
+
+    /// `PushQ RBP`, frame pointer setup, saves of `callee`, and the stack
+    /// allocation for the spill slots.
+    fn prologue(&self, callee: &[Register]) -> Vec<Instr> {
+        use InstrF::*;
+
+        let mut out = vec![
+            PushQ(Operand::Reg(Register::RBP)),
+            MovQ(Operand::Reg(Register::RSP), Operand::Reg(Register::RBP)),
+        ];
+        out.extend(callee.iter().cloned().map(|reg| PushQ(Operand::Reg(reg))));
+        if self.frame_size > 0 {
+            out.push(SubQ(Operand::Imm(self.frame_size), Operand::Reg(Register::RSP)));
+        }
+        out
+    }
+
+    /// Mirror image of [`Self::prologue`]: deallocates the spill slots and
+    /// restores `callee` and `RBP`, leaving the trailing `RetQ` to the caller.
+    fn epilogue(&self, callee: &[Register]) -> Vec<Instr> {
+        use InstrF::*;
+
+        let mut out = Vec::new();
+        if self.frame_size > 0 {
+            out.push(AddQ(Operand::Imm(self.frame_size), Operand::Reg(Register::RSP)));
+        }
+        out.extend(callee.iter().rev().cloned().map(|reg| PopQ(Operand::Reg(reg))));
+        out.push(PopQ(Operand::Reg(Register::RBP)));
+        out
+    }
+
+    /// Splice [`Self::epilogue`] in before every `RetQ` in `body`, for
+    /// functions that return from more than one block.
+    fn with_epilogue_before_returns(&self, body: Vec<Instr>, callee: &[Register]) -> Vec<Instr> {
+        let mut out = Vec::with_capacity(body.len());
+        for instr in body {
+            if matches!(instr, InstrF::RetQ) {
+                out.extend(self.epilogue(callee));
+            }
+            out.push(instr);
+        }
+        out
+    }
 }
 
 #[cfg(test)]
@@ -237,4 +1084,296 @@ mod tests {
         assert_eq!(live.before, HashSet::from(["z".into()]));
         assert_eq!(live.after, HashSet::default());
     }
+
+    #[test]
+    fn test_graph_coloring() {
+        use InstrF::*;
+
+        let block = Block(vec![
+            MovQ(32.into(), "x".into()),
+            MovQ(10.into(), "y".into()),
+            MovQ("x".into(), "z".into()),
+            AddQ("y".into(), "z".into()),
+            NegQ("z".into()),
+        ]);
+
+        let graph = InterferenceGraph::new(&block.liveness());
+        let coloring = graph.color(Register::allocatable().len()).unwrap();
+
+        // Every variable gets a register and interfering ones differ.
+        assert_eq!(coloring.len(), 3);
+        assert_ne!(coloring[&Name("x".into())], coloring[&Name("y".into())]);
+        assert_ne!(coloring[&Name("y".into())], coloring[&Name("z".into())]);
+    }
+
+    #[test]
+    fn test_cfg_liveness() {
+        use InstrF::*;
+
+        let program = Program(vec![
+            (
+                Label("start".into()),
+                Block(vec![MovQ(1.into(), "x".into()), Jmp(Label("end".into()))]),
+            ),
+            (
+                Label("end".into()),
+                Block(vec![MovQ("x".into(), "y".into()), NegQ("y".into()), RetQ]),
+            ),
+        ]);
+
+        let (live_in, live_out) = program.live_sets();
+
+        // `x` is defined in `start` and used in `end`, so it must flow across
+        // the jump: live out of `start` and live in to `end`.
+        assert_eq!(live_out[&Label("start".into())], HashSet::from(["x".into()]));
+        assert_eq!(live_in[&Label("end".into())], HashSet::from(["x".into()]));
+
+        let liveness = program.liveness();
+        let end = &liveness[&Label("end".into())];
+        assert_eq!(end[0].before, HashSet::from(["x".into()]));
+    }
+
+    #[test]
+    fn test_program_coloring() {
+        use InstrF::*;
+
+        let program = Program(vec![
+            (
+                Label("start".into()),
+                Block(vec![MovQ(1.into(), "x".into()), Jmp(Label("end".into()))]),
+            ),
+            (
+                Label("end".into()),
+                Block(vec![MovQ(2.into(), "y".into()), AddQ("y".into(), "x".into()), RetQ]),
+            ),
+        ]);
+
+        // `x` is defined in `start` and only interferes with `y` once it
+        // flows across the jump into `end`; coloring the whole program (not
+        // each block in isolation) is what lets that interference reach the
+        // allocator.
+        let coloring = program.color(Register::allocatable().len()).unwrap();
+        assert_eq!(coloring.len(), 2);
+        assert_ne!(coloring[&Name("x".into())], coloring[&Name("y".into())]);
+    }
+
+    #[test]
+    fn test_assemble_program() {
+        use InstrF::*;
+
+        let program = Program(vec![
+            (
+                Label("start".into()),
+                Block(vec![MovQ(1.into(), "x".into()), Jmp(Label("end".into()))]),
+            ),
+            (
+                Label("end".into()),
+                Block(vec![AddQ("x".into(), "y".into()), RetQ]),
+            ),
+        ]);
+
+        let spilled = HashSet::from([Name("x".into())]);
+        let allocation = HashMap::from([(Name("y".into()), Register::RBX)]);
+        let spiller = Spiller::new(&spilled);
+
+        let out = spiller.assemble_program(&program, &allocation);
+        assert_eq!(out.0.len(), 2);
+
+        let start = &out.0.iter().find(|(l, _)| *l == Label("start".into())).unwrap().1;
+        let end = &out.0.iter().find(|(l, _)| *l == Label("end".into())).unwrap().1;
+
+        // The prologue belongs to the entry block alone.
+        assert_eq!(start.0.first(), Some(&PushQ(Operand::Reg(Register::RBP))));
+        assert!(!end.0.contains(&PushQ(Operand::Reg(Register::RBP))));
+
+        // The epilogue sits right before `end`'s `RetQ`, not `start`'s `Jmp`.
+        assert_eq!(end.0.last(), Some(&RetQ));
+        assert_eq!(end.0[end.0.len() - 2], PopQ(Operand::Reg(Register::RBP)));
+        assert!(matches!(start.0.last(), Some(&Jmp(_))));
+
+        // No variable operands survive into either block.
+        assert!(!out.0.iter().any(|(_, block)| block.0.iter().any(|i| {
+            i.uses().iter().chain(i.defs().iter()).any(|op| matches!(op, Operand::Var(_)))
+        })));
+    }
+
+    #[test]
+    fn test_linear_scan() {
+        use InstrF::*;
+
+        let block = Block(vec![
+            MovQ(32.into(), "x".into()),
+            MovQ(10.into(), "y".into()),
+            MovQ("x".into(), "z".into()),
+            AddQ("y".into(), "z".into()),
+            NegQ("z".into()),
+        ]);
+
+        let scan = LinearScan::new(&block.liveness());
+
+        // `x` is defined at 0 and last used at 2; `z` spans 2..4.
+        let x = scan
+            .intervals()
+            .iter()
+            .find(|iv| iv.name == Name("x".into()))
+            .unwrap();
+        assert_eq!((x.start, x.end), (0, 2));
+
+        // With plenty of registers every variable lands in a register.
+        let alloc = scan.allocate(Register::allocatable().len());
+        assert!(matches!(alloc[&Name("z".into())], Operand::Reg(_)));
+
+        // With a single register the longer-lived interval wins and a shorter
+        // one is forced onto the stack.
+        let tight = scan.allocate(1);
+        assert!(tight.values().any(|op| matches!(op, Operand::Mem(Register::RBP, _))));
+    }
+
+    #[test]
+    fn test_coalescing() {
+        use InstrF::*;
+
+        let block = Block(vec![
+            MovQ(32.into(), "x".into()),
+            MovQ("x".into(), "y".into()),
+            AddQ(1.into(), "y".into()),
+        ]);
+
+        let k = Register::allocatable().len();
+        let mut graph = InterferenceGraph::new(&block.liveness());
+        graph.coalesce(k);
+
+        // The non-interfering move pair collapses to a single representative.
+        assert_eq!(
+            graph.representative(&Name("x".into())),
+            graph.representative(&Name("y".into()))
+        );
+
+        let colored = graph.color(k).unwrap();
+        let resolved: HashMap<Name, Register> = [Name("x".into()), Name("y".into())]
+            .into_iter()
+            .map(|name| {
+                let reg = colored[&graph.representative(&name)].clone();
+                (name, reg)
+            })
+            .collect();
+
+        // The redundant `MovQ x, y` is dropped once both share a register.
+        let optimized = block.remove_redundant_moves(&resolved);
+        assert_eq!(optimized.0.len(), 2);
+        assert!(!optimized
+            .0
+            .iter()
+            .any(|i| matches!(i, MovQ(Operand::Var(_), Operand::Var(_)))));
+    }
+
+    #[test]
+    fn test_spill_code() {
+        use InstrF::*;
+
+        let block = Block(vec![
+            MovQ(1.into(), "x".into()),
+            AddQ("x".into(), "y".into()),
+            RetQ,
+        ]);
+
+        let spilled = HashSet::from([Name("x".into())]);
+        let allocation = HashMap::from([(Name("y".into()), Register::RBX)]);
+
+        let spiller = Spiller::new(&spilled);
+        assert_eq!(spiller.frame_size(), 16);
+
+        let out = spiller.assemble(&block, &allocation);
+
+        // Prologue and epilogue frame the code.
+        assert_eq!(out.0.first(), Some(&PushQ(Operand::Reg(Register::RBP))));
+        assert_eq!(out.0.last(), Some(&RetQ));
+
+        // The callee-saved register in the allocation is saved and restored.
+        assert!(out.0.contains(&PushQ(Operand::Reg(Register::RBX))));
+        assert!(out.0.contains(&PopQ(Operand::Reg(Register::RBX))));
+
+        // The spilled variable is stored to and reloaded from its slot.
+        let slot = Operand::Mem(Register::RBP, -8);
+        assert!(out
+            .0
+            .iter()
+            .any(|i| matches!(i, MovQ(_, dst) if *dst == slot)));
+        assert!(out
+            .0
+            .iter()
+            .any(|i| matches!(i, MovQ(src, _) if *src == slot)));
+
+        // No variable operands survive into the assembled code.
+        assert!(!out.0.iter().any(|i| {
+            i.uses().iter().chain(i.defs().iter()).any(|op| matches!(op, Operand::Var(_)))
+        }));
+    }
+
+    #[test]
+    fn test_color_honors_k() {
+        use InstrF::*;
+
+        // x, y and z are pairwise live at once, forming a 3-clique that no
+        // 2-coloring can satisfy.
+        let block = Block(vec![
+            MovQ(1.into(), "x".into()),
+            MovQ(2.into(), "y".into()),
+            MovQ(3.into(), "z".into()),
+            AddQ("y".into(), "x".into()),
+            AddQ("z".into(), "y".into()),
+            AddQ("x".into(), "z".into()),
+            RetQ,
+        ]);
+
+        let graph = InterferenceGraph::new(&block.liveness());
+        assert!(graph.color(2).is_err());
+        assert!(graph.color(3).is_ok());
+    }
+
+    #[test]
+    fn test_spill_code_under_register_pressure() {
+        use InstrF::*;
+
+        // Fill every register `Spiller` is allowed to assume the allocator
+        // used, leaving none of them free for reloads.
+        let allocation: HashMap<Name, Register> = Register::allocatable()
+            .into_iter()
+            .take(Spiller::usable_registers())
+            .enumerate()
+            .map(|(i, reg)| (Name(format!("v{i}")), reg))
+            .collect();
+        assert_eq!(allocation.len(), Spiller::usable_registers());
+
+        let block = Block(vec![AddQ("x".into(), "y".into()), RetQ]);
+        let spilled = HashSet::from([Name("x".into()), Name("y".into())]);
+        let spiller = Spiller::new(&spilled);
+
+        // Both operands are spilled and need reloading at once; this used to
+        // panic with "no entry found for key" when scratch registers were
+        // computed from the (exhausted) unused-register set instead of the
+        // reserved tail of the palette.
+        let out = spiller.assemble(&block, &allocation);
+        assert!(!out.0.iter().any(|i| {
+            i.uses().iter().chain(i.defs().iter()).any(|op| matches!(op, Operand::Var(_)))
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved scratch register")]
+    fn test_spill_code_rejects_allocation_in_scratch_registers() {
+        use InstrF::*;
+
+        // Coloring with the full palette instead of `usable_registers()`
+        // hands a reserved scratch register to a live variable; `assemble`
+        // must catch that rather than silently clobbering it on reload.
+        let reserved = Register::allocatable()[Spiller::usable_registers()].clone();
+        let allocation = HashMap::from([(Name("y".into()), reserved)]);
+
+        let block = Block(vec![AddQ("x".into(), "y".into()), RetQ]);
+        let spilled = HashSet::from([Name("x".into())]);
+        let spiller = Spiller::new(&spilled);
+
+        spiller.assemble(&block, &allocation);
+    }
 }